@@ -1,8 +1,54 @@
+//! Companion macros for the `ctenv` crate
+//!
+//! `ctenv!` blindly splices the raw `.env` value as a token sequence, which only works for bare
+//! tokens such as integers. The macros below pair with `ctenv::Schema` (see `ctenv::run_with_schema`):
+//! the build script writes each value in a pre-quoted, typed form, and the matching macro here
+//! just `include!`s it as the literal of that type.
+
 #![no_std]
 
+/// Splices a key's raw `.env` value as a bare token sequence, e.g. for array lengths
 #[macro_export]
 macro_rules! ctenv {
     ($key:ident) => {
         include!(concat!(env!("OUT_DIR"), "/", stringify!($key)))
-    }
+    };
+}
+
+/// Splices a key declared as `ctenv::Type::Str` (or `Path`) as a `&str` literal
+#[macro_export]
+macro_rules! ctenv_str {
+    ($key:ident) => {{
+        const VALUE: &str = include!(concat!(env!("OUT_DIR"), "/", stringify!($key)));
+        VALUE
+    }};
+}
+
+/// Splices a key declared as `ctenv::Type::Bool` as a `bool` literal
+#[macro_export]
+macro_rules! ctenv_bool {
+    ($key:ident) => {{
+        const VALUE: bool = include!(concat!(env!("OUT_DIR"), "/", stringify!($key)));
+        VALUE
+    }};
+}
+
+/// Splices a key declared as `ctenv::Type::U32` as a `u32` literal
+#[macro_export]
+macro_rules! ctenv_u32 {
+    ($key:ident) => {{
+        const VALUE: u32 = include!(concat!(env!("OUT_DIR"), "/", stringify!($key)));
+        VALUE
+    }};
+}
+
+/// Reads a key through `env!`, tracked natively by rustc/Cargo instead of an `OUT_DIR` file
+///
+/// `$krate` must be spelled exactly as it appears in the `CTENV_$CRATE_$KEY` variable name (see
+/// the `ctenv` crate docs), i.e. uppercased with non-alphanumeric characters replaced by `_`.
+#[macro_export]
+macro_rules! ctenv_env {
+    ($krate:ident, $key:ident) => {
+        env!(concat!("CTENV_", stringify!($krate), "_", stringify!($key)))
+    };
 }
@@ -10,7 +10,7 @@
 //! // crate: foo
 //! // file: build.rs
 //!
-//! fn main() -> Result<(), Box<Error>> {
+//! fn main() -> Result<(), Box<dyn Error>> {
 //!     ctenv::run()?;
 //!
 //!     // ..
@@ -39,78 +39,475 @@
 //!
 //! - `cargo build` and you are done
 //!
-//! # Known issues
+//! # Defaults and overrides
 //!
-//! The dependency also needs a `.env` file or it won't build.
+//! A dependency can ship its own `.env` file next to its `Cargo.toml` to provide defaults for its
+//! own `$crate:$key` entries. The top level crate's `.env` then only needs to override the keys it
+//! actually cares about; anything it leaves out falls back to the dependency's default.
 //!
-//! There's no great to have the dependency communicate its dependents that some settings need to be
-//! set in a `.env` file, other than in its crate level documentation.
+//! Plain [`run`] has no notion of which keys a dependency actually needs, so a key that's absent
+//! from both layers is silently never written, and the `ctenv!`/`env!` use site fails later with a
+//! confusing "file/variable not found" error. **The clear "crate X requires key Y" diagnostic is a
+//! guarantee of [`run_with_schema`] only** -- `run` cannot offer it, since it never sees the set of
+//! keys a dependency expects. Prefer [`run_with_schema`] whenever a key is actually required.
 //!
-//! # Possible expansions
+//! # Variable interpolation
 //!
-//! Defaults and overrides. If a dependency contains a `.env` file those settings will be used
-//! *unless* the top level crate overrides them in its own `.env` file. This should be
-//! straightforward to implement but I don't know if it's actually a good idea or not.
+//! A value may reference another key with `${KEY}` / `$KEY`, the same way [dotenvy] does. The
+//! referenced name is first looked up among the keys already defined for the current crate, then,
+//! if it's qualified as `$crate:$key`, among any other crate's keys, and finally falls back to the
+//! process environment. A literal `$` is written with `\$`.
+//!
+//! [dotenvy]: https://crates.io/crates/dotenvy
+//!
+//! ```
+//! # crate: foo
+//! foo:PREFIX=/usr/local
+//! foo:BIN_DIR=${PREFIX}/bin
+//! ```
+//!
+//! # Environment variable overrides
+//!
+//! Following [twelve-factor] config-in-the-environment, any `$crate:$key` can be overridden at
+//! build time with an actual environment variable, named `CTENV_$CRATE_$KEY` (uppercased, with
+//! non-alphanumeric characters replaced by `_`). This lets CI/deployment pipelines inject
+//! compile-time configuration without editing `.env` files.
+//!
+//! [twelve-factor]: https://12factor.net/config
+//!
+//! ```
+//! $ CTENV_FOO_BUF_SZ=256 cargo build
+//! ```
+//!
+//! # Schema validation
+//!
+//! A dependency can declare the keys it requires, their types and optional defaults with a
+//! [`Schema`], and call [`run_with_schema`] instead of [`run`]. The build then fails early with a
+//! precise message instead of a confusing `include!` error further down the line.
+//!
+//! ```
+//! // crate: foo
+//! // file: build.rs
+//!
+//! fn main() -> Result<(), Box<dyn Error>> {
+//!     ctenv::run_with_schema(
+//!         ctenv::Schema::new().key("BUF_SZ", ctenv::Type::U32),
+//!     )?;
+//!
+//!     // ..
+//! }
+//! ```
+//!
+//! # Locating the top level `.env`
+//!
+//! The top level `.env` is found by calling [`dotenv_path`], which `run`/`run_with_schema` use
+//! internally: `CTENV_FILE`, if set, is used as-is; otherwise `OUT_DIR` (which Cargo always nests
+//! under a `target` directory, for *any* kind of dependency, registry ones included, whether or
+//! not `--target` is passed) is walked up to that `target` directory, whose parent is assumed to
+//! be the consumer's root. Only when `OUT_DIR` has no `target` component at all is the workspace
+//! root looked for instead, by walking up from `CARGO_MANIFEST_DIR` for the outermost directory
+//! containing a `Cargo.toml` -- this only helps when the dependency is a path/workspace member,
+//! since nothing above a registry checkout has a `Cargo.toml` of its own.
+//!
+//! A custom `build.target-dir` that isn't nested under the consumer's root is not detected when
+//! it is itself named `target`: its parent is taken as the root. When it is named something
+//! else, only a path/workspace dependency finds the `.env` through the fallback. Set `CTENV_FILE`
+//! explicitly in either case.
+//!
+//! # Native `env!` tracking
+//!
+//! Every key is also emitted as `cargo:rustc-env=CTENV_$CRATE_$KEY=$value`, the same name used
+//! for [environment variable overrides](#environment-variable-overrides). rustc records such
+//! variables as `env-dep`s in its dep-info, so Cargo rebuilds on just that key changing, without
+//! needing a file under `OUT_DIR`. Use the `ctenv_env!` macro (see `ctenv-macros`) to read it:
+//!
+//! ```
+//! // crate: foo
+//!
+//! let bin_dir: &str = ctenv_env!(FOO, BIN_DIR);
+//! ```
+//!
+//! The `OUT_DIR` file is still written alongside it, so `ctenv!`/`ctenv_bool!`/`ctenv_u32!`
+//! continue to work for values that must be spliced as non-string tokens (array lengths, etc).
 
-use std::{env, error::Error, fmt, fs, path::PathBuf};
+use std::{
+    collections::{hash_map::Entry as HashMapEntry, HashMap},
+    env,
+    error::Error,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
 
 /// Call this from your build script
-pub fn run() -> Result<(), Box<Error>> {
+///
+/// This does not validate that any particular key was set; a key missing from both `.env` layers
+/// is silently never written, and only surfaces later as a confusing `include!`/`env!` error at
+/// the use site. Use [`run_with_schema`] instead for a clear "crate X requires key Y" diagnostic.
+pub fn run() -> Result<(), Box<dyn Error>> {
+    run_impl(None)
+}
+
+/// Like [`run`], but validates the resolved configuration against `schema` first
+///
+/// Missing required keys fail the build with a message naming the crate, the key and its type;
+/// keys with a declared default are filled in instead. Values are also validated against their
+/// declared type and written to `OUT_DIR` in a normalized, pre-quoted form.
+pub fn run_with_schema(schema: Schema) -> Result<(), Box<dyn Error>> {
+    run_impl(Some(schema))
+}
+
+fn run_impl(schema: Option<Schema>) -> Result<(), Box<dyn Error>> {
     // the name of the crate that's currently being built
     let pkg_name = env::var("CARGO_PKG_NAME")?;
 
-    // This is a variable set by Cargo; it *usually* points to `$TOP_LEVEL_CRATE/target/..`
-    // It doesn't when the user sets `build.target-dir` in .cargo/config (and maybe also when doing
-    // `cargo install`?), but we are going to assume that such setting has not been set
     let out_dir = PathBuf::from(env::var("OUT_DIR")?);
 
-    // Extract `$TOP_LEVEL_CRATE` from `out_dir`
-    let mut path = out_dir.clone();
-    while path.file_name().and_then(|os| os.to_str()) != Some("target") {
-        path.pop();
+    let dep_dotenv = PathBuf::from(env::var("CARGO_MANIFEST_DIR")?).join(".env");
+    let top_dotenv = dotenv_path()?;
+
+    // first load the dependency's own defaults, then let the top level crate override them; the
+    // top level `.env` is also where cross-crate references (`$other_crate:$key`) get resolved
+    // from, so it's parsed in full rather than filtered down to `pkg_name`
+    let mut resolved = HashMap::new();
+    load_into(&dep_dotenv, Some(&pkg_name), &mut resolved)?;
+    load_into(&top_dotenv, None, &mut resolved)?;
+
+    if let Some(schema) = &schema {
+        for entry in &schema.entries {
+            let k = (pkg_name.clone(), entry.key.to_owned());
+
+            if let HashMapEntry::Vacant(vacant) = resolved.entry(k) {
+                // a `CTENV_$CRATE_$KEY` override can also satisfy a required key, not just a
+                // default: it should be possible to configure a dependency purely through the
+                // environment, without ever touching a `.env` file
+                if let Ok(value) = env::var(override_var_name(&pkg_name, entry.key)) {
+                    vacant.insert(value);
+                } else {
+                    match entry.default {
+                        Some(default) => {
+                            vacant.insert(default.to_owned());
+                        }
+                        None => {
+                            return Err(Box::new(SchemaError::Missing {
+                                krate: pkg_name,
+                                key: entry.key.to_owned(),
+                                ty: entry.ty,
+                            }));
+                        }
+                    }
+                }
+            }
+        }
     }
 
-    // at this point `path` should be `$TOP_LEVEL_CRATE/target`
-    path.pop();
-    path.push(".env");
+    for ((krate, key), value) in &resolved {
+        if krate == &pkg_name {
+            // let an actual environment variable override the `.env` value for this key
+            let override_var = override_var_name(krate, key);
+            let raw_value = env::var(&override_var).unwrap_or_else(|_| value.clone());
 
-    let dotenv = path;
+            // expose the key through `env!`/`ctenv_env!` natively; rustc records this as an
+            // `env-dep` in its dep-info, so Cargo rebuilds on just this key changing, for free
+            println!("cargo:rustc-env={}={}", override_var, raw_value);
 
-    for (i, line) in fs::read_to_string(&dotenv)?.lines().enumerate() {
-        if line.starts_with("#") {
-            // this is a comment; ignore
-            continue;
+            // also write the OUT_DIR-based, schema-normalized form for macros that splice the
+            // value as a non-string token (`ctenv!`, `ctenv_bool!`, `ctenv_u32!`)
+            let mut file_value = raw_value;
+            if let Some(schema) = &schema {
+                if let Some(entry) = schema
+                    .entries
+                    .iter()
+                    .find(|entry| entry.key == key.as_str())
+                {
+                    file_value = normalize(entry.ty, krate, key, &file_value)?;
+                }
+            }
+
+            // XXX Maybe prefix the key with `ctenv` to avoid collisions with other build artifacts?
+            fs::write(out_dir.join(key), file_value)?;
+
+            // rerun the build script (and so rebuild the dependency) when this key's override changes
+            println!("cargo:rerun-if-env-changed={}", override_var);
         }
+    }
 
-        // NOTE poor man's `try { .. }` block
-        let (krate, key, value) = (|| -> Option<_> {
-            // Syntax: $crate:$key=$value
-            let mut parts = line.splitn(2, ':');
+    // needed to make Cargo rebuild the dependency if either layer changes
+    if dep_dotenv.exists() {
+        println!("cargo:rerun-if-changed={}", dep_dotenv.display());
+    }
+    if top_dotenv.exists() {
+        println!("cargo:rerun-if-changed={}", top_dotenv.display());
+    }
 
-            let krate = parts.next()?;
-            let key_value = parts.next()?;
+    Ok(())
+}
 
-            let mut parts = key_value.splitn(2, '=');
-            let key = parts.next()?;
-            let value = parts.next()?;
+/// Declares the keys a dependency requires, their types and optional defaults; see
+/// [`run_with_schema`]
+#[derive(Default)]
+pub struct Schema {
+    entries: Vec<Entry>,
+}
 
-            Some((krate, key, value))
-        })()
-        .ok_or(ParseError { line: i + 1 })?;
+struct Entry {
+    key: &'static str,
+    ty: Type,
+    default: Option<&'static str>,
+}
 
-        // Is this for us?
-        if krate == pkg_name {
-            // XXX Maybe prefix the key with `ctenv` to avoid collisions with other build artifacts?
-            fs::write(out_dir.join(key), value)?;
+impl Schema {
+    /// Creates an empty schema
+    pub fn new() -> Self {
+        Schema::default()
+    }
+
+    /// Declares a required `key` of type `ty`
+    pub fn key(mut self, key: &'static str, ty: Type) -> Self {
+        self.entries.push(Entry {
+            key,
+            ty,
+            default: None,
+        });
+        self
+    }
+
+    /// Declares a `key` of type `ty` with a `default`, used when neither `.env` layer nor a
+    /// `CTENV_$CRATE_$KEY` override sets it
+    pub fn key_with_default(mut self, key: &'static str, ty: Type, default: &'static str) -> Self {
+        self.entries.push(Entry {
+            key,
+            ty,
+            default: Some(default),
+        });
+        self
+    }
+}
+
+/// The type of a schema key; see [`Schema`]
+#[derive(Clone, Copy, Debug)]
+pub enum Type {
+    /// `u32`
+    U32,
+    /// `bool`
+    Bool,
+    /// A string, e.g. for use with `ctenv_str!`
+    Str,
+    /// A filesystem path; stored like `Str` but documents intent
+    Path,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Type::U32 => "u32",
+            Type::Bool => "bool",
+            Type::Str => "string",
+            Type::Path => "path",
+        })
+    }
+}
+
+/// Validates `value` against `ty` and returns the normalized form written to `OUT_DIR`
+fn normalize(ty: Type, krate: &str, key: &str, value: &str) -> Result<String, SchemaError> {
+    let bad_type = || SchemaError::BadType {
+        krate: krate.to_owned(),
+        key: key.to_owned(),
+        ty,
+        value: value.to_owned(),
+    };
+
+    match ty {
+        Type::U32 => value
+            .parse::<u32>()
+            .map(|v| v.to_string())
+            .map_err(|_| bad_type()),
+        Type::Bool => value
+            .parse::<bool>()
+            .map(|v| v.to_string())
+            .map_err(|_| bad_type()),
+        Type::Str | Type::Path => Ok(format!("{:?}", value)),
+    }
+}
+
+/// Builds the `CTENV_$CRATE_$KEY` environment variable name used to override `krate:key`
+fn override_var_name(krate: &str, key: &str) -> String {
+    format!("CTENV_{}_{}", krate, key)
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Locates the top level crate's `.env`
+///
+/// `CTENV_FILE`, if set, is used as-is. Otherwise `OUT_DIR` -- which Cargo always nests under a
+/// `target` directory, even for a registry dependency whose checkout has nothing above it, and
+/// regardless of whether `--target` inserts an extra triple segment -- is walked up to that
+/// `target` directory, whose parent is assumed to be the consumer's root. A relocated
+/// `build.target-dir` that is still named `target` is not detected. Only when `OUT_DIR` has no
+/// `target` component at all does this fall back to walking up from `CARGO_MANIFEST_DIR` for the
+/// outermost directory containing a `Cargo.toml`, which only helps for a path/workspace dependency.
+pub fn dotenv_path() -> Result<PathBuf, Box<dyn Error>> {
+    if let Ok(file) = env::var("CTENV_FILE") {
+        return Ok(PathBuf::from(file));
+    }
+
+    if let Some(root) = env::var("OUT_DIR")
+        .ok()
+        .and_then(|out_dir| target_dir(Path::new(&out_dir)))
+        .and_then(|target_dir| target_dir.parent().map(Path::to_path_buf))
+    {
+        return Ok(root.join(".env"));
+    }
+
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR")?);
+
+    workspace_root(&manifest_dir)
+        .map(|root| root.join(".env"))
+        .ok_or_else(|| {
+            Box::new(DiscoveryError {
+                start: manifest_dir,
+            }) as Box<dyn Error>
+        })
+}
+
+/// Returns the `target` directory that `out_dir` (an `OUT_DIR`) is nested under
+///
+/// `OUT_DIR` is always `$TARGET_DIR/$PROFILE/build/$PKG-$HASH/out`, except when cross-compiling
+/// with `--target`, where Cargo inserts an extra `$TRIPLE` segment before `$PROFILE`. Rather than
+/// assume a fixed ancestor count, walk up looking for the `target` component itself, which is
+/// present either way.
+fn target_dir(out_dir: &Path) -> Option<PathBuf> {
+    out_dir
+        .ancestors()
+        .find(|dir| dir.file_name() == Some("target".as_ref()))
+        .map(Path::to_path_buf)
+}
+
+/// Returns the outermost ancestor of `start` (inclusive) that contains a `Cargo.toml`
+fn workspace_root(start: &Path) -> Option<PathBuf> {
+    let mut root = None;
+    let mut dir = start.to_path_buf();
+
+    loop {
+        if dir.join("Cargo.toml").is_file() {
+            root = Some(dir.clone());
+        }
+
+        if !dir.pop() {
+            break;
         }
     }
 
-    // needed to make Cargo rebuild the dependency if the top level .env changes
-    println!("cargo:rerun-if-changed={}", dotenv.display());
+    root
+}
+
+/// Parses `path`, if it exists, and inserts every `(krate, key) -> value` entry into `resolved`,
+/// expanding `${..}` / `$..` references as they're encountered.
+///
+/// If `only` is `Some(pkg_name)`, entries for any other crate are skipped; this is used for the
+/// dependency's own `.env`, which only ever declares defaults for itself.
+fn load_into(
+    path: &Path,
+    only: Option<&str>,
+    resolved: &mut HashMap<(String, String), String>,
+) -> Result<(), Box<dyn Error>> {
+    if !path.exists() {
+        // this layer is optional
+        return Ok(());
+    }
+
+    for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with("#") {
+            // blank lines and comments are ignored, the same way dotenvy does it
+            continue;
+        }
+
+        // Syntax: $crate:$key=$value
+        let (krate, key_value) = line.split_once(':').ok_or(ParseError { line: i + 1 })?;
+        let (key, value) = key_value
+            .split_once('=')
+            .ok_or(ParseError { line: i + 1 })?;
+
+        if let Some(only) = only {
+            if krate != only {
+                continue;
+            }
+        }
+
+        let value = expand(value, krate, resolved)?;
+        resolved.insert((krate.to_owned(), key.to_owned()), value);
+    }
 
     Ok(())
 }
 
+/// Expands `${name}` / `$name` references in `raw`, as seen from `krate`
+///
+/// `name` is looked up, in order, among `krate`'s already-resolved keys, among any other crate's
+/// keys (only when qualified as `other_crate:key`) and finally in the process environment. `\$`
+/// escapes to a literal `$`.
+fn expand(
+    raw: &str,
+    krate: &str,
+    resolved: &HashMap<(String, String), String>,
+) -> Result<String, ReferenceError> {
+    let mut out = String::with_capacity(raw.len());
+    let mut chars = raw.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' && chars.peek() == Some(&'$') {
+            chars.next();
+            out.push('$');
+            continue;
+        }
+
+        if c != '$' {
+            out.push(c);
+            continue;
+        }
+
+        let braced = chars.peek() == Some(&'{');
+        if braced {
+            chars.next();
+        }
+
+        let mut name = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_alphanumeric() || c == '_' || c == ':' {
+                name.push(c);
+                chars.next();
+            } else {
+                break;
+            }
+        }
+
+        if braced && chars.next() != Some('}') {
+            return Err(ReferenceError { name });
+        }
+
+        let value = if let Some(i) = name.find(':') {
+            resolved
+                .get(&(name[..i].to_owned(), name[i + 1..].to_owned()))
+                .cloned()
+        } else {
+            resolved
+                .get(&(krate.to_owned(), name.clone()))
+                .cloned()
+                .or_else(|| env::var(&name).ok())
+        };
+
+        out.push_str(&value.ok_or_else(|| ReferenceError { name: name.clone() })?);
+    }
+
+    Ok(out)
+}
+
 #[derive(Debug)]
 struct ParseError {
     line: usize,
@@ -123,3 +520,75 @@ impl fmt::Display for ParseError {
         write!(f, "parse error at line {}", self.line)
     }
 }
+
+/// A `${name}` / `$name` reference in a `.env` value that couldn't be resolved
+#[derive(Debug)]
+struct ReferenceError {
+    name: String,
+}
+
+impl Error for ReferenceError {}
+
+impl fmt::Display for ReferenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unresolved reference to `{}`", self.name)
+    }
+}
+
+/// No ancestor of `start` contains a `Cargo.toml`; see [`dotenv_path`]
+#[derive(Debug)]
+struct DiscoveryError {
+    start: PathBuf,
+}
+
+impl Error for DiscoveryError {}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "could not find a top level Cargo.toml above `{}`; set CTENV_FILE to override",
+            self.start.display()
+        )
+    }
+}
+
+/// A violation of a [`Schema`]
+#[derive(Debug)]
+enum SchemaError {
+    Missing {
+        krate: String,
+        key: String,
+        ty: Type,
+    },
+    BadType {
+        krate: String,
+        key: String,
+        ty: Type,
+        value: String,
+    },
+}
+
+impl Error for SchemaError {}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchemaError::Missing { krate, key, ty } => write!(
+                f,
+                "crate `{}` requires key `{}` ({}); set `{}:{}=...` in .env",
+                krate, key, ty, krate, key
+            ),
+            SchemaError::BadType {
+                krate,
+                key,
+                ty,
+                value,
+            } => write!(
+                f,
+                "crate `{}` key `{}` is not a valid {}: `{}`",
+                krate, key, ty, value
+            ),
+        }
+    }
+}